@@ -1,22 +1,32 @@
 use axum::{
     Json, Router,
-    extract::{Path, State},
-    routing::post,
+    extract::{
+        Path, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::Response,
+    routing::{get, post},
 };
+use rand::{RngCore, rngs::OsRng};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::Arc};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, broadcast};
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
     error::{ApiError, ApiResult},
+    metrics::Metrics,
     punch::punch_coordinator,
-    relay::relay_server,
+    quic_relay::quic_relay_server,
+    relay::{RelayRateLimit, relay_server},
+    utils::{ClientSecret, encode_hex},
 };
 
 mod error;
+mod metrics;
 mod punch;
+mod quic_relay;
 mod relay;
 mod utils;
 
@@ -24,9 +34,39 @@ type RoomId = u64;
 type ClientId = u128;
 type ClientString = String;
 
+fn random_secret() -> ClientSecret {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// Number of non-host members a room accepts when `create_room` doesn't
+/// specify a capacity, preserving the old two-player behavior.
+const DEFAULT_ROOM_CAPACITY: usize = 1;
+
+const DEFAULT_RELAY_PACKETS_PER_SEC: f64 = 200.0;
+const DEFAULT_RELAY_BYTES_PER_SEC: f64 = 2_000_000.0;
+
+/// Reads a rate limit from the environment, falling back to the relay's
+/// defaults if unset or unparseable.
+fn relay_rate_limit_from_env() -> RelayRateLimit {
+    fn env_f64(key: &str, default: f64) -> f64 {
+        std::env::var(key)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    }
+
+    RelayRateLimit {
+        packets_per_sec: env_f64("RELAY_PACKETS_PER_SEC", DEFAULT_RELAY_PACKETS_PER_SEC),
+        bytes_per_sec: env_f64("RELAY_BYTES_PER_SEC", DEFAULT_RELAY_BYTES_PER_SEC),
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
     inner: Arc<RwLock<AppStateInner>>,
+    metrics: Arc<Metrics>,
 }
 
 struct AppStateInner {
@@ -35,32 +75,91 @@ struct AppStateInner {
 }
 
 impl AppStateInner {
-    pub fn get_peer(&self, client_id: ClientId) -> Option<ClientId> {
-        let client = self.clients.get(&client_id)?;
-        let room = self.rooms.get(&client.room)?;
-        let guest_id = room.client?;
-        let other_id = if room.host == client_id {
-            guest_id
-        } else {
-            room.host
+    /// Every other member of `client_id`'s room, in no particular order.
+    pub fn get_peers(&self, client_id: ClientId) -> Vec<ClientId> {
+        let Some(client) = self.clients.get(&client_id) else {
+            return Vec::new();
         };
-        Some(other_id)
+        let Some(room) = self.rooms.get(&client.room) else {
+            return Vec::new();
+        };
+        room.all_members().filter(|&id| id != client_id).collect()
+    }
+
+    pub fn client_secret(&self, client_id: ClientId) -> Option<ClientSecret> {
+        Some(self.clients.get(&client_id)?.secret)
     }
+
+    /// Whether `client_id` is currently a member of a room, as opposed to
+    /// merely having an authenticated UDP/QUIC binding. Relaying on behalf of
+    /// a client that isn't in any room would turn the relay into an open
+    /// reflector, so callers should check this before forwarding.
+    pub fn in_room(&self, client_id: ClientId) -> bool {
+        self.clients
+            .get(&client_id)
+            .is_some_and(|c| self.rooms.contains_key(&c.room))
+    }
+}
+
+/// Capacity of each room's event broadcast channel. Subscribers that fall
+/// this far behind just miss the oldest events rather than blocking senders.
+const ROOM_EVENT_CHANNEL_CAPACITY: usize = 32;
+
+/// Typed notifications pushed to `/room/{room_id}/events` subscribers so
+/// clients don't have to poll for membership/traversal changes.
+#[derive(Clone, Serialize)]
+#[serde(tag = "type")]
+enum RoomEvent {
+    PeerJoined { client_id: ClientId },
+    PeerLeft { client_id: ClientId },
+    HostChanged { new_host: ClientId },
+    RelayFallbackAvailable,
 }
 
-#[derive(Default)]
 struct RoomState {
     host: ClientId,
-    client: Option<ClientId>,
+    /// Non-host members, bounded by `capacity`.
+    members: Vec<ClientId>,
+    capacity: usize,
+    events: broadcast::Sender<RoomEvent>,
+}
+
+impl RoomState {
+    fn new(host: ClientId, capacity: usize) -> Self {
+        let (events, _) = broadcast::channel(ROOM_EVENT_CHANNEL_CAPACITY);
+        Self {
+            host,
+            members: Vec::new(),
+            capacity,
+            events,
+        }
+    }
+
+    fn all_members(&self) -> impl Iterator<Item = ClientId> + '_ {
+        std::iter::once(self.host).chain(self.members.iter().copied())
+    }
+
+    fn is_full(&self) -> bool {
+        self.members.len() >= self.capacity
+    }
+
+    /// Publishes an event to subscribers. No-op if nobody is listening.
+    fn publish(&self, event: RoomEvent) {
+        let _ = self.events.send(event);
+    }
 }
 
 struct ClientState {
     room: RoomId,
+    secret: ClientSecret,
 }
 
 impl ClientState {
-    fn new(room_id: RoomId) -> Self {
-        Self { room: room_id }
+    fn new(room_id: RoomId, secret: ClientSecret) -> Self {
+        Self {
+            room: room_id,
+            secret,
+        }
     }
 }
 
@@ -69,7 +168,8 @@ async fn main() -> anyhow::Result<()> {
     let rooms = HashMap::new();
     let clients = HashMap::new();
     let inner = Arc::new(RwLock::new(AppStateInner { rooms, clients }));
-    let state = AppState { inner };
+    let metrics = Metrics::new()?;
+    let state = AppState { inner, metrics };
 
     tracing_subscriber::registry()
         .with(
@@ -85,13 +185,20 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     tokio::spawn(punch_coordinator("0.0.0.0:9000".parse()?, state.clone()));
-    tokio::spawn(relay_server("0.0.0.0:9001".parse()?, state.clone()));
+    tokio::spawn(relay_server(
+        "0.0.0.0:9001".parse()?,
+        state.clone(),
+        relay_rate_limit_from_env(),
+    ));
+    tokio::spawn(quic_relay_server("0.0.0.0:9002".parse()?, state.clone()));
 
     let app = Router::new()
         .layer(TraceLayer::new_for_http())
         .route("/create_room", post(create_room))
         .route("/join_room/{room_id}", post(join_room))
         .route("/leave_room", post(leave_room))
+        .route("/room/{room_id}/events", get(room_events))
+        .route("/metrics", get(metrics_handler))
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
@@ -99,14 +206,25 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn metrics_handler(State(st): State<AppState>) -> String {
+    st.metrics.render()
+}
+
 #[derive(Deserialize)]
 struct CreateRoomReq {
     client_id: ClientString,
+    /// Maximum number of non-host members the room will accept. Defaults to
+    /// [`DEFAULT_ROOM_CAPACITY`] (a single guest) when omitted.
+    #[serde(default)]
+    capacity: Option<usize>,
 }
 
 #[derive(Serialize)]
 struct CreateRoomResp {
     room_id: RoomId,
+    /// Hex-encoded per-client secret the client must hold onto and use to
+    /// answer UDP bind challenges from the relay/punch coordinator.
+    secret: ClientString,
 }
 
 async fn create_room(
@@ -118,21 +236,23 @@ async fn create_room(
     };
     let mut state = st.inner.write().await;
     let room_id = state.rooms.len() as u64;
+    let capacity = req.capacity.unwrap_or(DEFAULT_ROOM_CAPACITY);
 
-    state.rooms.insert(
-        room_id,
-        RoomState {
-            host: client_id,
-            client: None,
-        },
-    );
     state
+        .rooms
+        .insert(room_id, RoomState::new(client_id, capacity));
+    let secret = state
         .clients
         .entry(client_id)
         .and_modify(|e| e.room = room_id)
-        .or_insert(ClientState::new(room_id));
+        .or_insert_with(|| ClientState::new(room_id, random_secret()))
+        .secret;
+    st.metrics.rooms_open.set(state.rooms.len() as i64);
 
-    Ok(Json(CreateRoomResp { room_id }))
+    Ok(Json(CreateRoomResp {
+        room_id,
+        secret: encode_hex(&secret),
+    }))
 }
 
 #[derive(Deserialize)]
@@ -141,7 +261,11 @@ struct JoinRoomReq {
 }
 
 #[derive(Serialize)]
-struct JoinRoomResp {}
+struct JoinRoomResp {
+    /// Hex-encoded per-client secret, same meaning as the one returned from
+    /// `create_room`.
+    secret: ClientString,
+}
 
 async fn join_room(
     State(st): State<AppState>,
@@ -163,18 +287,61 @@ async fn join_room(
     let Some(room) = state.rooms.get_mut(&room_id) else {
         return Err(ApiError::NotFound("room not found"));
     };
-    if room.client.is_some() {
+    if room.is_full() {
         return Err(ApiError::Conflict("room is full"));
     }
 
-    room.client = Some(client_id);
-    state
+    room.members.push(client_id);
+    room.publish(RoomEvent::PeerJoined { client_id });
+    if room.is_full() {
+        room.publish(RoomEvent::RelayFallbackAvailable);
+    }
+    let secret = state
         .clients
         .entry(client_id)
         .and_modify(|e| e.room = room_id)
-        .or_insert(ClientState::new(room_id));
+        .or_insert_with(|| ClientState::new(room_id, random_secret()))
+        .secret;
 
-    Ok(Json(JoinRoomResp {}))
+    Ok(Json(JoinRoomResp {
+        secret: encode_hex(&secret),
+    }))
+}
+
+/// Subscribes the caller to a room's membership/traversal events over a
+/// WebSocket, so it can react immediately instead of polling.
+async fn room_events(
+    State(st): State<AppState>,
+    Path(room_id): Path<u64>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let rx = {
+        let state = st.inner.read().await;
+        let Some(room) = state.rooms.get(&room_id) else {
+            return Err(ApiError::NotFound("room not found"));
+        };
+        room.events.subscribe()
+    };
+
+    Ok(ws.on_upgrade(move |socket| forward_room_events(socket, rx)))
+}
+
+async fn forward_room_events(mut socket: WebSocket, mut rx: broadcast::Receiver<RoomEvent>) {
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            // lagged subscribers just resume from the next event; a closed
+            // channel means the room is gone and there's nothing left to send
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let Ok(payload) = serde_json::to_string(&event) else {
+            continue;
+        };
+        if socket.send(Message::Text(payload.into())).await.is_err() {
+            break;
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -195,26 +362,28 @@ async fn leave_room(State(st): State<AppState>, Json(req): Json<LeaveRoomReq>) -
         return Err(ApiError::NotFound("client's room no longer exists"));
     };
 
-    if room.client.is_some_and(|id| id == client_id) {
-        // client was the client of the room
-        room.client = None;
+    if let Some(idx) = room.members.iter().position(|&id| id == client_id) {
+        // client was a non-host member of the room
+        room.members.remove(idx);
+        room.publish(RoomEvent::PeerLeft { client_id });
     } else {
         if client_id != room.host {
             return Err(ApiError::Internal("client's cached room was incorrect"));
         }
         // client was the host of the room
-        match room.client {
-            // if there was a peer, that peer becomes the host
-            Some(peer) => {
-                room.host = peer;
-                room.client = None;
-            }
-            // otherwise, the room is empty, and should be removed
-            None => {
-                state.rooms.remove(&cur_room);
-            }
+        room.publish(RoomEvent::PeerLeft { client_id });
+        if room.members.is_empty() {
+            // the room is empty, and should be removed
+            state.rooms.remove(&cur_room);
+        } else {
+            // the oldest remaining member becomes the new host
+            room.host = room.members.remove(0);
+            room.publish(RoomEvent::HostChanged {
+                new_host: room.host,
+            });
         }
     }
     state.clients.remove(&client_id);
+    st.metrics.rooms_open.set(state.rooms.len() as i64);
     Ok(Json(()))
 }
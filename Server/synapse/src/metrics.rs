@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+/// Shared Prometheus metrics for the room API, relay, and punch coordinator.
+pub struct Metrics {
+    registry: Registry,
+    pub relay_clients: IntGauge,
+    pub punch_clients: IntGauge,
+    pub rooms_open: IntGauge,
+    pub bytes_relayed_total: IntCounter,
+    pub datagrams_relayed_total: IntCounter,
+    pub punch_pairs_emitted_total: IntCounter,
+    pub stale_clients_reaped_total: IntCounterVec,
+    pub bind_auth_failures_total: IntCounterVec,
+    pub relay_rate_limited_total: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Arc<Self>> {
+        let registry = Registry::new();
+
+        let relay_clients = IntGauge::new("synapse_relay_clients", "Active relay-bound clients")?;
+        let punch_clients = IntGauge::new("synapse_punch_clients", "Active punch-bound clients")?;
+        let rooms_open = IntGauge::new("synapse_rooms_open", "Rooms currently open")?;
+        let bytes_relayed_total =
+            IntCounter::new("synapse_bytes_relayed_total", "Bytes forwarded by the relay")?;
+        let datagrams_relayed_total = IntCounter::new(
+            "synapse_datagrams_relayed_total",
+            "Datagrams forwarded by the relay",
+        )?;
+        let punch_pairs_emitted_total = IntCounter::new(
+            "synapse_punch_pairs_emitted_total",
+            "Punch peer packets emitted by the coordinator",
+        )?;
+        let stale_clients_reaped_total = IntCounterVec::new(
+            Opts::new(
+                "synapse_stale_clients_reaped_total",
+                "Clients removed by the periodic staleness sweep",
+            ),
+            &["subsystem"],
+        )?;
+        let bind_auth_failures_total = IntCounterVec::new(
+            Opts::new(
+                "synapse_bind_auth_failures_total",
+                "Bind challenge responses that failed MAC verification",
+            ),
+            &["subsystem"],
+        )?;
+        let relay_rate_limited_total = IntCounter::new(
+            "synapse_relay_rate_limited_total",
+            "Relay datagrams dropped for exceeding a client's rate limit",
+        )?;
+
+        registry.register(Box::new(relay_clients.clone()))?;
+        registry.register(Box::new(punch_clients.clone()))?;
+        registry.register(Box::new(rooms_open.clone()))?;
+        registry.register(Box::new(bytes_relayed_total.clone()))?;
+        registry.register(Box::new(datagrams_relayed_total.clone()))?;
+        registry.register(Box::new(punch_pairs_emitted_total.clone()))?;
+        registry.register(Box::new(stale_clients_reaped_total.clone()))?;
+        registry.register(Box::new(bind_auth_failures_total.clone()))?;
+        registry.register(Box::new(relay_rate_limited_total.clone()))?;
+
+        Ok(Arc::new(Self {
+            registry,
+            relay_clients,
+            punch_clients,
+            rooms_open,
+            bytes_relayed_total,
+            datagrams_relayed_total,
+            punch_pairs_emitted_total,
+            stale_clients_reaped_total,
+            bind_auth_failures_total,
+            relay_rate_limited_total,
+        }))
+    }
+
+    /// Renders the registry in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metric_families = self.registry.gather();
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&metric_families, &mut buf)
+            .expect("encoding to an in-memory buffer cannot fail");
+        String::from_utf8(buf).expect("prometheus text exposition format is valid UTF-8")
+    }
+}
@@ -4,22 +4,72 @@ use tokio::{
     time::{self, Instant},
 };
 
+use rand::{RngCore, rngs::OsRng};
+
 use crate::{
     AppState, ClientId,
-    utils::{UdpClientState, parse_client_id},
+    utils::{PendingChallenge, UdpClientState, parse_client_id, verify_mac},
 };
 
 #[repr(u8)]
 enum OutgoingPacketType {
     FoundPeer = 0x1,
     WaitingPeer = 0x2,
+    Challenge = 0x3,
+}
+
+#[repr(u8)]
+enum IncomingPacketType {
+    Ping = 0x1,
+    BindAuth = 0x2,
+}
+
+impl TryFrom<u8> for IncomingPacketType {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x1 => Ok(IncomingPacketType::Ping),
+            0x2 => Ok(IncomingPacketType::BindAuth),
+            _ => Err(()),
+        }
+    }
+}
+
+enum IncomingPacket {
+    Ping(ClientId),
+    BindAuth(ClientId, [u8; 32]),
 }
 
-fn encode_waiting<'a>(out: &'a mut [u8]) -> &'a [u8] {
+fn parse_incoming(buf: &[u8]) -> Option<IncomingPacket> {
+    if buf.is_empty() {
+        return None;
+    }
+    match IncomingPacketType::try_from(buf[0]).ok()? {
+        IncomingPacketType::Ping => Some(IncomingPacket::Ping(parse_client_id(&buf[1..])?)),
+        IncomingPacketType::BindAuth => {
+            let client_id = parse_client_id(&buf[1..])?;
+            if buf.len() < 1 + 16 + 32 {
+                return None;
+            }
+            let mut mac = [0u8; 32];
+            mac.copy_from_slice(&buf[17..49]);
+            Some(IncomingPacket::BindAuth(client_id, mac))
+        }
+    }
+}
+
+fn encode_waiting(out: &mut [u8]) -> &[u8] {
     out[0] = OutgoingPacketType::WaitingPeer as u8;
     &out[..1]
 }
 
+fn encode_challenge<'a>(nonce: &[u8; 16], out: &'a mut [u8]) -> &'a [u8] {
+    out[0] = OutgoingPacketType::Challenge as u8;
+    out[1..17].copy_from_slice(nonce);
+    &out[..17]
+}
+
 fn encode_socket<'a>(peer: SocketAddr, out: &'a mut [u8]) -> &'a [u8] {
     out[0] = OutgoingPacketType::FoundPeer as u8;
     match peer.ip() {
@@ -44,6 +94,7 @@ pub async fn punch_coordinator(bind: SocketAddr, st: AppState) -> anyhow::Result
     const RX_BUF_SIZE: usize = 2048;
     const TX_BUF_SIZE: usize = 64;
     const STALE_AFTER: Duration = Duration::from_secs(60);
+    const CHALLENGE_TTL: Duration = Duration::from_secs(5);
     const CLEANUP_EVERY: Duration = Duration::from_secs(5);
 
     let sock = UdpSocket::bind(bind).await?;
@@ -52,6 +103,7 @@ pub async fn punch_coordinator(bind: SocketAddr, st: AppState) -> anyhow::Result
 
     let mut punch_clients: HashMap<ClientId, UdpClientState> = HashMap::new();
     let mut addr_to_client: HashMap<SocketAddr, ClientId> = HashMap::new();
+    let mut pending_challenges: HashMap<ClientId, PendingChallenge> = HashMap::new();
 
     let mut cleanup_tick = time::interval(CLEANUP_EVERY);
     cleanup_tick.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
@@ -75,7 +127,14 @@ pub async fn punch_coordinator(bind: SocketAddr, st: AppState) -> anyhow::Result
                 }
                 tracing::debug!("Cleaned {} stale punch clients", stale_ids.len());
 
+                st.metrics
+                    .stale_clients_reaped_total
+                    .with_label_values(&["punch"])
+                    .inc_by(stale_ids.len() as u64);
                 stale_ids.clear();
+
+                pending_challenges.retain(|_, c| now.duration_since(c.issued_at) <= CHALLENGE_TTL);
+                st.metrics.punch_clients.set(punch_clients.len() as i64);
             }
 
             res = sock.recv_from(&mut rx) => {
@@ -84,36 +143,94 @@ pub async fn punch_coordinator(bind: SocketAddr, st: AppState) -> anyhow::Result
                     Err(_) => continue,
                 };
 
-                let Some(client_id) = parse_client_id(&rx[..n]) else {
+                let Some(pkt) = parse_incoming(&rx[..n]) else {
                     continue;
                 };
 
-                tracing::debug!("Received punch from client {} from address {}", client_id, src);
+                let client_id = match pkt {
+                    IncomingPacket::Ping(client_id) => {
+                        tracing::debug!("Received punch from client {} from address {}", client_id, src);
+
+                        let already_bound = punch_clients
+                            .get(&client_id)
+                            .is_some_and(|e| e.udp_addr == src);
 
-                match punch_clients.get_mut(&client_id) {
-                    Some(e) => {
-                        if e.udp_addr != src {
-                            tracing::debug!("Punch client {client_id} migrated from address {} to {}", e.udp_addr, src);
+                        if already_bound {
+                            if let Some(e) = punch_clients.get_mut(&client_id) {
+                                e.last_seen = Instant::now();
+                            }
+                            client_id
+                        } else {
+                            // New binding or a migration: challenge the
+                            // sender to prove it holds the client's secret
+                            // before trusting `src`.
+                            let mut nonce = [0u8; 16];
+                            OsRng.fill_bytes(&mut nonce);
+                            pending_challenges.insert(client_id, PendingChallenge { nonce, issued_at: Instant::now() });
 
-                            addr_to_client.remove(&e.udp_addr);
-                            addr_to_client.insert(src, client_id);
-                            e.udp_addr = src;
+                            let pkt = encode_challenge(&nonce, &mut tx);
+                            let _ = sock.send_to(pkt, src).await;
+                            continue;
                         }
-                        e.last_seen = Instant::now();
                     }
-                    None => {
-                        punch_clients.insert(
-                            client_id,
-                            UdpClientState {
-                                udp_addr: src,
-                                last_seen: Instant::now(),
-                            },
-                        );
-                        addr_to_client.insert(src, client_id);
+
+                    IncomingPacket::BindAuth(client_id, mac) => {
+                        let Some(challenge) = pending_challenges.get(&client_id) else {
+                            continue;
+                        };
+                        if Instant::now().duration_since(challenge.issued_at) > CHALLENGE_TTL {
+                            pending_challenges.remove(&client_id);
+                            continue;
+                        }
+
+                        let secret = {
+                            let inner = st.inner.read().await;
+                            inner.client_secret(client_id)
+                        };
+                        let Some(secret) = secret else {
+                            continue;
+                        };
+                        if !verify_mac(&secret, &challenge.nonce, &mac) {
+                            tracing::debug!("Punch bind auth failed for client {}", client_id);
+                            st.metrics
+                                .bind_auth_failures_total
+                                .with_label_values(&["punch"])
+                                .inc();
+                            continue;
+                        }
+                        pending_challenges.remove(&client_id);
+
+                        match punch_clients.get_mut(&client_id) {
+                            Some(e) => {
+                                if e.udp_addr != src {
+                                    tracing::debug!("Punch client {client_id} migrated from address {} to {}", e.udp_addr, src);
+
+                                    addr_to_client.remove(&e.udp_addr);
+                                    addr_to_client.insert(src, client_id);
+                                    e.udp_addr = src;
+                                }
+                                e.last_seen = Instant::now();
+                            }
+                            None => {
+                                punch_clients.insert(
+                                    client_id,
+                                    UdpClientState {
+                                        udp_addr: src,
+                                        last_seen: Instant::now(),
+                                    },
+                                );
+                                addr_to_client.insert(src, client_id);
+                            }
+                        }
+                        st.metrics.punch_clients.set(punch_clients.len() as i64);
+                        client_id
                     }
-                }
+                };
 
-                let maybe_pairs: Option<[(SocketAddr, SocketAddr); 2]> = {
+                // Emit a packet for every ordered pair of room members that
+                // have both registered a punch endpoint, so clients can form
+                // a full mesh instead of a single fixed pairing.
+                let pairs: Vec<(SocketAddr, SocketAddr)> = {
                     let inner = st.inner.read().await;
                     let Some(client) = inner.clients.get(&client_id) else {
                         continue;
@@ -121,30 +238,40 @@ pub async fn punch_coordinator(bind: SocketAddr, st: AppState) -> anyhow::Result
                     let Some(room) = inner.rooms.get(&client.room) else {
                         continue;
                     };
-                    let Some(guest_id) = room.client else {
-                        continue;
-                    };
-                    let host_id = room.host;
-                    let Some(host_punch) = punch_clients.get(&host_id) else {
-                        continue;
-                    };
-                    let Some(guest_punch) = punch_clients.get(&guest_id) else {
-                        continue;
-                    };
-                    Some([
-                        (host_punch.udp_addr, guest_punch.udp_addr),
-                        (guest_punch.udp_addr, host_punch.udp_addr),
-                    ])
+                    let members: Vec<ClientId> = room.all_members().collect();
+
+                    let mut pairs = Vec::new();
+                    for &a in &members {
+                        let Some(a_ep) = punch_clients.get(&a) else {
+                            continue;
+                        };
+                        for &b in &members {
+                            if a == b {
+                                continue;
+                            }
+                            let Some(b_ep) = punch_clients.get(&b) else {
+                                continue;
+                            };
+                            pairs.push((a_ep.udp_addr, b_ep.udp_addr));
+                        }
+                    }
+                    pairs
                 };
 
-                if let Some(pairs) = maybe_pairs {
-                    for (dst, peer) in pairs {
-                        tracing::debug!("Forwarding punch peer {} for client {}", peer, client_id);
+                let client_has_peer = punch_clients
+                    .get(&client_id)
+                    .is_some_and(|ep| pairs.iter().any(|&(dst, _)| dst == ep.udp_addr));
 
-                        let pkt = encode_socket(peer, &mut tx);
-                        let _ = sock.send_to(pkt, dst).await;
-                    }
-                } else {
+                for (dst, peer) in pairs.iter().copied() {
+                    tracing::debug!("Forwarding punch peer {} for client {}", peer, client_id);
+
+                    let pkt = encode_socket(peer, &mut tx);
+                    let _ = sock.send_to(pkt, dst).await;
+                    st.metrics.punch_pairs_emitted_total.inc();
+                }
+                if !client_has_peer {
+                    // no other member of `client_id`'s room has registered a
+                    // punch endpoint yet: let it know to keep punching
                     let pkt = encode_waiting(&mut tx);
                     let _ = sock.send_to(pkt, src).await;
                 }
@@ -0,0 +1,172 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use quinn::{Endpoint, RecvStream, SendStream, ServerConfig, rustls::pki_types::PrivatePkcs8KeyDer};
+use rand::{RngCore, rngs::OsRng};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::RwLock,
+};
+
+use crate::{
+    AppState, ClientId,
+    utils::verify_mac,
+};
+
+/// Live QUIC connections, keyed by the `ClientId` each one authenticated as.
+/// Shared across every accepted connection's task, separate from the UDP
+/// relay's `relay_clients` map since the two transports track different kinds
+/// of endpoints (a socket address vs. an open connection).
+type QuicClients = Arc<RwLock<HashMap<ClientId, quinn::Connection>>>;
+
+/// Runs a QUIC relay listener alongside [`crate::relay::relay_server`] for
+/// clients behind NATs that drop unsolicited UDP. Shares `AppState`'s
+/// room/peer lookup so both transports see the same room topology.
+pub async fn quic_relay_server(bind: SocketAddr, st: AppState) -> anyhow::Result<()> {
+    let endpoint = Endpoint::server(self_signed_server_config()?, bind)?;
+    let quic_clients: QuicClients = Arc::new(RwLock::new(HashMap::new()));
+
+    while let Some(incoming) = endpoint.accept().await {
+        let st = st.clone();
+        let quic_clients = quic_clients.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(incoming, st, quic_clients).await {
+                tracing::debug!("QUIC relay connection ended: {err}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(
+    incoming: quinn::Incoming,
+    st: AppState,
+    quic_clients: QuicClients,
+) -> anyhow::Result<()> {
+    let conn = incoming.await?;
+
+    // The first bidirectional stream is the control stream: the client must
+    // complete the same challenge-response bind handshake the UDP relay
+    // uses before this connection is bound to a `ClientId`.
+    let (mut send, mut recv) = conn.accept_bi().await?;
+    let client_id = authenticate(&st, &mut send, &mut recv).await?;
+    tracing::debug!("QUIC relay client {client_id} authenticated from {}", conn.remote_address());
+
+    quic_clients.write().await.insert(client_id, conn.clone());
+
+    let result = relay_loop(&st, &quic_clients, client_id, &conn).await;
+
+    quic_clients.write().await.remove(&client_id);
+    result
+}
+
+/// Verifies proof of ownership of `client_id`'s secret over the control
+/// stream, mirroring [`crate::relay::RelaySend::Bind`]'s UDP handshake.
+async fn authenticate(
+    st: &AppState,
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+) -> anyhow::Result<ClientId> {
+    let mut id_buf = [0u8; 16];
+    recv.read_exact(&mut id_buf).await?;
+    let client_id = ClientId::from_be_bytes(id_buf);
+
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    send.write_all(&nonce).await?;
+
+    let mut mac = [0u8; 32];
+    recv.read_exact(&mut mac).await?;
+
+    let secret = {
+        let inner = st.inner.read().await;
+        inner.client_secret(client_id)
+    };
+    let Some(secret) = secret else {
+        anyhow::bail!("unknown client id {client_id}");
+    };
+    if !verify_mac(&secret, &nonce, &mac) {
+        anyhow::bail!("bind auth failed for client {client_id}");
+    }
+
+    Ok(client_id)
+}
+
+/// Forwards every stream and datagram this connection sends to every other
+/// member of its room until the connection closes.
+async fn relay_loop(
+    st: &AppState,
+    quic_clients: &QuicClients,
+    client_id: ClientId,
+    conn: &quinn::Connection,
+) -> anyhow::Result<()> {
+    loop {
+        tokio::select! {
+            biased;
+            datagram = conn.read_datagram() => {
+                let datagram = datagram?;
+                tracing::debug!("Forwarding QUIC datagram from client {client_id}");
+                forward_datagram(st, quic_clients, client_id, datagram).await;
+                st.metrics.datagrams_relayed_total.inc();
+            }
+
+            stream = conn.accept_bi() => {
+                let (mut peer_send, mut peer_recv) = stream?;
+                let Ok(buf) = peer_recv.read_to_end(64 * 1024).await else {
+                    continue;
+                };
+                tracing::debug!("Forwarding QUIC stream from client {client_id}");
+                forward_stream(st, quic_clients, client_id, &buf).await;
+                st.metrics.bytes_relayed_total.inc_by(buf.len() as u64);
+                let _ = peer_send.finish();
+            }
+        }
+    }
+}
+
+async fn forward_datagram(
+    st: &AppState,
+    quic_clients: &QuicClients,
+    client_id: ClientId,
+    payload: bytes::Bytes,
+) {
+    let peers = {
+        let inner = st.inner.read().await;
+        inner.get_peers(client_id)
+    };
+    let clients = quic_clients.read().await;
+    for peer in peers {
+        if let Some(peer_conn) = clients.get(&peer) {
+            let _ = peer_conn.send_datagram(payload.clone());
+        }
+    }
+}
+
+async fn forward_stream(st: &AppState, quic_clients: &QuicClients, client_id: ClientId, payload: &[u8]) {
+    let peers = {
+        let inner = st.inner.read().await;
+        inner.get_peers(client_id)
+    };
+    let clients = quic_clients.read().await;
+    for peer in peers {
+        let Some(peer_conn) = clients.get(&peer) else {
+            continue;
+        };
+        let Ok(mut send) = peer_conn.open_uni().await else {
+            continue;
+        };
+        let _ = send.write_all(payload).await;
+        let _ = send.finish();
+    }
+}
+
+/// Generates a throwaway self-signed certificate for the QUIC listener.
+/// It encrypts the transport, but since a new one is generated on every
+/// start it does not let clients verify the server; client identity is
+/// established separately via the [`authenticate`] handshake.
+fn self_signed_server_config() -> anyhow::Result<ServerConfig> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+    let server_config = ServerConfig::with_single_cert(vec![cert.cert.into()], key.into())?;
+    Ok(server_config)
+}
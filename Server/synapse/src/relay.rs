@@ -1,5 +1,6 @@
 use std::{collections::HashMap, net::SocketAddr, time::Duration};
 
+use rand::{RngCore, rngs::OsRng};
 use tokio::{
     net::UdpSocket,
     time::{self, Instant},
@@ -7,13 +8,14 @@ use tokio::{
 
 use crate::{
     AppState, ClientId,
-    utils::{UdpClientState, parse_client_id},
+    utils::{PendingChallenge, UdpClientState, parse_client_id, verify_mac},
 };
 
 #[repr(u8)]
 pub enum RelaySendType {
     Bind = 0x1,
     Relay = 0x2,
+    BindAuth = 0x3,
 }
 
 impl TryFrom<u8> for RelaySendType {
@@ -23,14 +25,92 @@ impl TryFrom<u8> for RelaySendType {
         match value {
             0x1 => Ok(RelaySendType::Bind),
             0x2 => Ok(RelaySendType::Relay),
+            0x3 => Ok(RelaySendType::BindAuth),
             _ => Err(()),
         }
     }
 }
 
+/// Packet types the relay sends back to clients that aren't a bare echo of
+/// the request type (see [`RelaySend::Bind`]'s ack, which just echoes `0x1`).
+#[repr(u8)]
+enum RelayRecvType {
+    Challenge = 0x4,
+}
+
+/// Per-client token-bucket limits for `RelaySend::Relay` forwarding, so a
+/// single client can't saturate the relay or use it as a reflector.
+#[derive(Clone, Copy)]
+pub struct RelayRateLimit {
+    pub packets_per_sec: f64,
+    pub bytes_per_sec: f64,
+}
+
+impl RelayRateLimit {
+    fn full_bucket(&self) -> RateBucket {
+        RateBucket {
+            packet_tokens: self.packets_per_sec,
+            byte_tokens: self.bytes_per_sec,
+        }
+    }
+}
+
+/// A relay-bound client's UDP endpoint plus its token-bucket state. Kept
+/// separate from [`UdpClientState`] since only the relay (not the punch
+/// coordinator) needs to account for forwarded traffic.
+struct RelayClientState {
+    ep: UdpClientState,
+    packet_tokens: f64,
+    byte_tokens: f64,
+}
+
+struct RateBucket {
+    packet_tokens: f64,
+    byte_tokens: f64,
+}
+
+impl RelayClientState {
+    fn new(udp_addr: SocketAddr, rate_limit: RelayRateLimit) -> Self {
+        let RateBucket {
+            packet_tokens,
+            byte_tokens,
+        } = rate_limit.full_bucket();
+        Self {
+            ep: UdpClientState {
+                udp_addr,
+                last_seen: Instant::now(),
+            },
+            packet_tokens,
+            byte_tokens,
+        }
+    }
+
+    /// Refills the bucket for elapsed time since `last_seen` (capped at one
+    /// second's worth of tokens) then tries to withdraw the cost of a
+    /// `len`-byte packet. Returns whether the packet may proceed.
+    fn try_consume(&mut self, len: usize, rate_limit: RelayRateLimit) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.ep.last_seen).as_secs_f64();
+        self.ep.last_seen = now;
+
+        self.packet_tokens = (self.packet_tokens + elapsed * rate_limit.packets_per_sec)
+            .min(rate_limit.packets_per_sec);
+        self.byte_tokens =
+            (self.byte_tokens + elapsed * rate_limit.bytes_per_sec).min(rate_limit.bytes_per_sec);
+
+        if self.packet_tokens < 1.0 || self.byte_tokens < len as f64 {
+            return false;
+        }
+        self.packet_tokens -= 1.0;
+        self.byte_tokens -= len as f64;
+        true
+    }
+}
+
 #[derive(Debug)]
 pub enum RelaySend<'a> {
     Bind(ClientId),
+    BindAuth(ClientId, [u8; 32]),
     Relay(&'a [u8]),
 }
 
@@ -44,22 +124,39 @@ impl<'a> RelaySend<'a> {
                 let client_id = parse_client_id(&buf[1..])?;
                 RelaySend::Bind(client_id)
             }
+            RelaySendType::BindAuth => {
+                let client_id = parse_client_id(&buf[1..])?;
+                if buf.len() < 1 + 16 + 32 {
+                    return None;
+                }
+                let mut mac = [0u8; 32];
+                mac.copy_from_slice(&buf[17..49]);
+                RelaySend::BindAuth(client_id, mac)
+            }
             RelaySendType::Relay => RelaySend::Relay(buf),
         };
         Some(msg)
     }
 }
 
-pub async fn relay_server(bind: SocketAddr, st: AppState) -> anyhow::Result<()> {
+pub async fn relay_server(
+    bind: SocketAddr,
+    st: AppState,
+    rate_limit: RelayRateLimit,
+) -> anyhow::Result<()> {
     const RX_BUF_SIZE: usize = 2048;
+    const TX_BUF_SIZE: usize = 64;
     const STALE_AFTER: Duration = Duration::from_secs(60);
+    const CHALLENGE_TTL: Duration = Duration::from_secs(5);
     const CLEANUP_EVERY: Duration = Duration::from_secs(5);
 
     let sock = UdpSocket::bind(bind).await?;
     let mut rx = [0u8; RX_BUF_SIZE];
+    let mut tx = [0u8; TX_BUF_SIZE];
 
-    let mut relay_clients: HashMap<ClientId, UdpClientState> = HashMap::new();
+    let mut relay_clients: HashMap<ClientId, RelayClientState> = HashMap::new();
     let mut addr_to_client: HashMap<SocketAddr, ClientId> = HashMap::new();
+    let mut pending_challenges: HashMap<ClientId, PendingChallenge> = HashMap::new();
 
     let mut cleanup_tick = time::interval(CLEANUP_EVERY);
     cleanup_tick.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
@@ -72,18 +169,25 @@ pub async fn relay_server(bind: SocketAddr, st: AppState) -> anyhow::Result<()>
             _ = cleanup_tick.tick() => {
                 let now = Instant::now();
                 for (&client_id, ep) in relay_clients.iter() {
-                    if now.duration_since(ep.last_seen) > STALE_AFTER {
+                    if now.duration_since(ep.ep.last_seen) > STALE_AFTER {
                         stale_ids.push(client_id);
                     }
                 }
                 for client_id in stale_ids.iter() {
                     if let Some(ep) = relay_clients.remove(&client_id) {
-                        addr_to_client.remove(&ep.udp_addr);
+                        addr_to_client.remove(&ep.ep.udp_addr);
                     }
                 }
                 tracing::debug!("Cleaned {} stale relay clients", stale_ids.len());
 
+                st.metrics
+                    .stale_clients_reaped_total
+                    .with_label_values(&["relay"])
+                    .inc_by(stale_ids.len() as u64);
                 stale_ids.clear();
+
+                pending_challenges.retain(|_, c| now.duration_since(c.issued_at) <= CHALLENGE_TTL);
+                st.metrics.relay_clients.set(relay_clients.len() as i64);
             }
 
             res = sock.recv_from(&mut rx) => {
@@ -100,56 +204,112 @@ pub async fn relay_server(bind: SocketAddr, st: AppState) -> anyhow::Result<()>
                     RelaySend::Bind(client_id) => {
                         tracing::debug!("Received relay bind request for {} from address {}", client_id, src);
 
+                        let already_bound = relay_clients
+                            .get(&client_id)
+                            .is_some_and(|e| e.ep.udp_addr == src);
+
+                        if already_bound {
+                            if let Some(e) = relay_clients.get_mut(&client_id) {
+                                e.ep.last_seen = Instant::now();
+                            }
+                            let _ = sock.send_to(&rx[0..1], src).await;
+                            continue;
+                        }
+
+                        // New binding or a migration: don't trust `src` yet,
+                        // challenge it to prove it holds the client's secret.
+                        let mut nonce = [0u8; 16];
+                        OsRng.fill_bytes(&mut nonce);
+                        pending_challenges.insert(client_id, PendingChallenge { nonce, issued_at: Instant::now() });
+
+                        tx[0] = RelayRecvType::Challenge as u8;
+                        tx[1..17].copy_from_slice(&nonce);
+                        let _ = sock.send_to(&tx[..17], src).await;
+                    }
+
+                    RelaySend::BindAuth(client_id, mac) => {
+                        tracing::debug!("Received relay bind auth for {} from address {}", client_id, src);
+
+                        let Some(challenge) = pending_challenges.get(&client_id) else {
+                            continue;
+                        };
+                        if Instant::now().duration_since(challenge.issued_at) > CHALLENGE_TTL {
+                            pending_challenges.remove(&client_id);
+                            continue;
+                        }
+
+                        let secret = {
+                            let inner = st.inner.read().await;
+                            inner.client_secret(client_id)
+                        };
+                        let Some(secret) = secret else {
+                            continue;
+                        };
+                        if !verify_mac(&secret, &challenge.nonce, &mac) {
+                            tracing::debug!("Relay bind auth failed for client {}", client_id);
+                            st.metrics
+                                .bind_auth_failures_total
+                                .with_label_values(&["relay"])
+                                .inc();
+                            continue;
+                        }
+                        pending_challenges.remove(&client_id);
+
                         match relay_clients.get_mut(&client_id) {
                             Some(e) => {
-                                if e.udp_addr != src {
-                                    tracing::debug!("Relay client {client_id} migrated from address {} to {}", e.udp_addr, src);
+                                if e.ep.udp_addr != src {
+                                    tracing::debug!("Relay client {client_id} migrated from address {} to {}", e.ep.udp_addr, src);
 
-                                    addr_to_client.remove(&e.udp_addr);
+                                    addr_to_client.remove(&e.ep.udp_addr);
                                     addr_to_client.insert(src, client_id);
-                                    e.udp_addr = src;
+                                    e.ep.udp_addr = src;
                                 }
-                                e.last_seen = Instant::now();
+                                e.ep.last_seen = Instant::now();
                             }
                             None => {
-                                relay_clients.insert(
-                                    client_id,
-                                    UdpClientState {
-                                        udp_addr: src,
-                                        last_seen: Instant::now(),
-                                    },
-                                );
+                                relay_clients.insert(client_id, RelayClientState::new(src, rate_limit));
                                 addr_to_client.insert(src, client_id);
                             }
                         }
-                        // respond with bindack
-                        let _ = sock.send_to(&rx[0..1], src).await;
+                        st.metrics.relay_clients.set(relay_clients.len() as i64);
+                        let _ = sock.send_to(&[RelaySendType::Bind as u8], src).await;
                     }
 
                     RelaySend::Relay(buf) => {
                         tracing::debug!("Received relay request from address {}", src);
 
                         let Some(client_id) = addr_to_client.get(&src).copied() else {
+                            // not a bound client: refuse to act as an open reflector
                             continue;
                         };
                         let Some(ep) = relay_clients.get_mut(&client_id) else {
                             continue;
                         };
-                        ep.last_seen = Instant::now();
 
-                        let peer_ep = {
+                        if !ep.try_consume(buf.len(), rate_limit) {
+                            tracing::debug!("Rate limiting relay client {}", client_id);
+                            st.metrics.relay_rate_limited_total.inc();
+                            continue;
+                        }
+
+                        let peer_addrs: Vec<SocketAddr> = {
                             let inner = st.inner.read().await;
-                            let Some(peer) = inner.get_peer(client_id) else {
+                            if !inner.in_room(client_id) {
                                 continue;
-                            };
-                            let Some(peer_ep) = relay_clients.get_mut(&peer) else {
-                                continue;
-                            };
-                            peer_ep
+                            }
+                            inner
+                                .get_peers(client_id)
+                                .into_iter()
+                                .filter_map(|peer| relay_clients.get(&peer).map(|ep| ep.ep.udp_addr))
+                                .collect()
                         };
-                        tracing::debug!("Forwarding relay request from client {} to addr {}", client_id, peer_ep.udp_addr);
-                        // forward entire datagram (includes 0x2 relay id)
-                        let _ = sock.send_to(buf, peer_ep.udp_addr).await;
+                        for peer_addr in peer_addrs {
+                            tracing::debug!("Forwarding relay request from client {} to addr {}", client_id, peer_addr);
+                            // forward entire datagram (includes 0x2 relay id)
+                            let _ = sock.send_to(buf, peer_addr).await;
+                            st.metrics.datagrams_relayed_total.inc();
+                            st.metrics.bytes_relayed_total.inc_by(buf.len() as u64);
+                        }
                     }
                 }
             }
@@ -1,14 +1,29 @@
-use std::net::SocketAddr;
+use std::{fmt::Write as _, net::SocketAddr};
 
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use tokio::time::Instant;
 
 use crate::ClientId;
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// Per-client secret shared only between the client and the server, used to
+/// authenticate UDP bind/migrate requests via HMAC-SHA256.
+pub type ClientSecret = [u8; 32];
+
 pub struct UdpClientState {
     pub udp_addr: SocketAddr,
     pub last_seen: Instant,
 }
 
+/// A challenge issued to a client mid-bind, awaiting the matching
+/// `HMAC-SHA256(secret, nonce)` response.
+pub struct PendingChallenge {
+    pub nonce: [u8; 16],
+    pub issued_at: Instant,
+}
+
 #[inline]
 pub fn parse_client_id(buf: &[u8]) -> Option<ClientId> {
     if buf.len() < 16 {
@@ -18,3 +33,25 @@ pub fn parse_client_id(buf: &[u8]) -> Option<ClientId> {
     id_bytes.copy_from_slice(&buf[..16]);
     Some(u128::from_be_bytes(id_bytes))
 }
+
+/// Computes `HMAC-SHA256(secret, nonce)`, the answer to a bind challenge.
+/// The server checks answers with [`verify_mac`], which does not call this.
+pub fn compute_mac(secret: &ClientSecret, nonce: &[u8; 16]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(nonce);
+    mac.finalize().into_bytes().into()
+}
+
+pub fn verify_mac(secret: &ClientSecret, nonce: &[u8; 16], candidate: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(nonce);
+    mac.verify_slice(candidate).is_ok()
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(out, "{b:02x}").expect("writing to a String cannot fail");
+    }
+    out
+}